@@ -8,12 +8,16 @@
 // except according to those terms.
 
 use tokenizer::{XmlTokenizerOpts, XmlTokenizer};
-use tree_builder::{TreeSink, XmlTreeBuilder, XmlTreeBuilderOpts};
+use tree_builder::{TreeSink, XmlTreeBuilder, XmlTreeBuilderOpts, NamespaceMap};
 
 use std::borrow::Cow;
 use std::mem;
 
+#[cfg(not(feature = "encoding_rs"))]
 use encoding::{self, EncodingRef};
+#[cfg(feature = "encoding_rs")]
+use encoding_rs;
+use string_cache::QualName;
 use tendril;
 use tendril::{StrTendril, ByteTendril};
 use tendril::stream::{TendrilSink, Utf8LossyDecoder, LossyDecoder};
@@ -43,6 +47,33 @@ pub fn parse_document<Sink>(sink: Sink, opts: XmlParseOpts) -> XmlParser<Sink>
     XmlParser { tokenizer: tok}
 }
 
+/// Parse an XML fragment and send results to a `TreeSink`.
+///
+/// This is the `innerXML`/`innerHTML`-style counterpart to `parse_document`:
+/// the fragment is parsed with `context_namespaces` as its in-scope namespace
+/// prefix bindings, and its nodes are appended directly under `sink`'s
+/// document, so `sink`'s output is exactly the fragment's children, with no
+/// wrapper element around them. `context_name` is accepted but not used: no
+/// element is created for it, and it adds no namespace binding of its own.
+///
+/// ## Example
+///
+/// ```ignore
+/// let mut sink = MySink;
+/// parse_fragment(&mut sink, Default::default(), context_name, NamespaceMap::default());
+/// ```
+pub fn parse_fragment<Sink>(sink: Sink,
+                             opts: XmlParseOpts,
+                             context_name: QualName,
+                             context_namespaces: NamespaceMap)
+                             -> XmlParser<Sink>
+    where Sink: TreeSink {
+
+    let tb = XmlTreeBuilder::new_fragment(sink, context_name, context_namespaces, opts.tree_builder);
+    let tok = XmlTokenizer::new(tb, opts.tokenizer);
+    XmlParser { tokenizer: tok}
+}
+
 /// An XML parser,
 /// ready to receive Unicode input through the `tendril::TendrilSink` trait’s methods.
 pub struct XmlParser<Sink> where Sink: TreeSink {
@@ -81,11 +112,11 @@ impl<Sink: TreeSink> XmlParser<Sink> {
     /// Wrap this parser into a `TendrilSink` that accepts bytes
     /// and tries to detect the correct character encoding.
     ///
-    /// Currently this looks for a Byte Order Mark,
+    /// `BytesOpts::force_encoding`, if set, skips sniffing entirely.
+    /// Otherwise this looks for a Byte Order Mark,
     /// then uses `BytesOpts::transport_layer_encoding`,
+    /// then looks for the `encoding` pseudo-attribute of a leading XML declaration,
     /// then falls back to UTF-8.
-    ///
-    /// FIXME(https://github.com/servo/html5ever/issues/18): this should look for `<meta>` elements
     pub fn from_bytes(self, opts: BytesOpts) -> BytesParser<Sink> {
         BytesParser {
             state: BytesParserState::Initial { parser: self },
@@ -99,7 +130,12 @@ impl<Sink: TreeSink> XmlParser<Sink> {
 pub struct BytesOpts {
     /// The character encoding specified by the transport layer, if any.
     /// In HTTP for example, this is the `charset` parameter of the `Content-Type` response header.
-    pub transport_layer_encoding: Option<EncodingRef>,
+    pub transport_layer_encoding: Option<Encoding>,
+    /// Force this encoding and skip sniffing entirely, if set.
+    ///
+    /// Unlike `transport_layer_encoding`, this takes unconditional priority
+    /// over the byte order mark, the XML declaration, and everything else.
+    pub force_encoding: Option<Encoding>,
 }
 
 /// An HTML parser,
@@ -121,6 +157,7 @@ enum BytesParserState<Sink> where Sink: TreeSink {
     },
     Parsing {
         decoder: LossyDecoder<XmlParser<Sink>>,
+        encoding: Encoding,
     },
     Transient
 }
@@ -131,7 +168,7 @@ impl<Sink: TreeSink> BytesParser<Sink> {
         match self.state {
             BytesParserState::Initial { ref parser } => parser,
             BytesParserState::Buffering { ref parser, .. } => parser,
-            BytesParserState::Parsing { ref decoder } => decoder.inner_sink(),
+            BytesParserState::Parsing { ref decoder, .. } => decoder.inner_sink(),
             BytesParserState::Transient => unreachable!(),
         }
     }
@@ -141,7 +178,23 @@ impl<Sink: TreeSink> BytesParser<Sink> {
         match self.state {
             BytesParserState::Initial { ref mut parser } => parser,
             BytesParserState::Buffering { ref mut parser, .. } => parser,
-            BytesParserState::Parsing { ref mut decoder } => decoder.inner_sink_mut(),
+            BytesParserState::Parsing { ref mut decoder, .. } => decoder.inner_sink_mut(),
+            BytesParserState::Transient => unreachable!(),
+        }
+    }
+
+    /// The character encoding this parser has committed to, if it has
+    /// started decoding yet.
+    ///
+    /// Returns `None` while still buffering the prescan window, and
+    /// `Some` once enough input (or a non-empty `process_unicode` chunk)
+    /// has forced a decision. `finish` consumes the parser, so an encoding
+    /// chosen only at `finish` cannot be read back.
+    pub fn chosen_encoding(&self) -> Option<Encoding> {
+        match self.state {
+            BytesParserState::Initial { .. } => None,
+            BytesParserState::Buffering { .. } => None,
+            BytesParserState::Parsing { encoding, .. } => Some(encoding),
             BytesParserState::Transient => unreachable!(),
         }
     }
@@ -153,7 +206,7 @@ impl<Sink: TreeSink> BytesParser<Sink> {
         if t.is_empty() {
             return  // Don’t prevent buffering/encoding detection
         }
-        if let BytesParserState::Parsing { ref mut decoder } = self.state {
+        if let BytesParserState::Parsing { ref mut decoder, .. } = self.state {
             decoder.inner_sink_mut().process(t)
         } else {
             match mem::replace(&mut self.state, BytesParserState::Transient) {
@@ -163,7 +216,7 @@ impl<Sink: TreeSink> BytesParser<Sink> {
                 }
                 BytesParserState::Buffering { parser, buffer } => {
                     self.start_parsing(parser, buffer);
-                    if let BytesParserState::Parsing { ref mut decoder } = self.state {
+                    if let BytesParserState::Parsing { ref mut decoder, .. } = self.state {
                         decoder.inner_sink_mut().process(t)
                     } else {
                         unreachable!()
@@ -178,13 +231,13 @@ impl<Sink: TreeSink> BytesParser<Sink> {
         let encoding = detect_encoding(&buffer, &self.opts);
         let mut decoder = LossyDecoder::new(encoding, parser);
         decoder.process(buffer);
-        self.state = BytesParserState::Parsing { decoder: decoder }
+        self.state = BytesParserState::Parsing { decoder: decoder, encoding: encoding }
     }
 }
 
 impl<Sink: TreeSink> TendrilSink<tendril::fmt::Bytes> for BytesParser<Sink> {
     fn process(&mut self, t: ByteTendril) {
-        if let &mut BytesParserState::Parsing { ref mut decoder } = &mut self.state {
+        if let &mut BytesParserState::Parsing { ref mut decoder, .. } = &mut self.state {
             return decoder.process(t)
         }
         let (parser, buffer) = match mem::replace(&mut self.state, BytesParserState::Transient) {
@@ -209,7 +262,7 @@ impl<Sink: TreeSink> TendrilSink<tendril::fmt::Bytes> for BytesParser<Sink> {
         match self.state {
             BytesParserState::Initial { ref mut parser } => parser.error(desc),
             BytesParserState::Buffering { ref mut parser, .. } => parser.error(desc),
-            BytesParserState::Parsing { ref mut decoder } => decoder.error(desc),
+            BytesParserState::Parsing { ref mut decoder, .. } => decoder.error(desc),
             BytesParserState::Transient => unreachable!(),
         }
     }
@@ -225,32 +278,160 @@ impl<Sink: TreeSink> TendrilSink<tendril::fmt::Bytes> for BytesParser<Sink> {
                 decoder.process(buffer);
                 decoder.finish()
             },
-            BytesParserState::Parsing { decoder } => decoder.finish(),
+            BytesParserState::Parsing { decoder, .. } => decoder.finish(),
             BytesParserState::Transient => unreachable!(),
         }
     }
 }
 
-/// How many bytes does detect_encoding() need
-// FIXME(#18): should be 1024 for <meta> elements.
-const PRESCAN_BYTES: u32 = 3;
+/// The character encoding type used throughout this module.
+///
+/// By default this is `encoding::EncodingRef`, from the `encoding` crate.
+/// Building with the `encoding_rs` feature switches it to
+/// `&'static encoding_rs::Encoding` instead, and makes `from_bytes` drive the
+/// `encoding_rs`-backed `tendril::stream::LossyDecoder` rather than the
+/// `encoding`-crate one, so downstream users (notably Servo) can parse XML
+/// without pulling in the legacy `encoding` crate.
+#[cfg(not(feature = "encoding_rs"))]
+pub type Encoding = EncodingRef;
+#[cfg(feature = "encoding_rs")]
+pub type Encoding = &'static encoding_rs::Encoding;
+
+#[cfg(not(feature = "encoding_rs"))]
+fn utf_8() -> Encoding { encoding::all::UTF_8 }
+#[cfg(feature = "encoding_rs")]
+fn utf_8() -> Encoding { encoding_rs::UTF_8 }
+
+#[cfg(not(feature = "encoding_rs"))]
+fn utf_16be() -> Encoding { encoding::all::UTF_16BE }
+#[cfg(feature = "encoding_rs")]
+fn utf_16be() -> Encoding { encoding_rs::UTF_16BE }
+
+#[cfg(not(feature = "encoding_rs"))]
+fn utf_16le() -> Encoding { encoding::all::UTF_16LE }
+#[cfg(feature = "encoding_rs")]
+fn utf_16le() -> Encoding { encoding_rs::UTF_16LE }
+
+/// Resolve a WHATWG encoding label (e.g. from an XML declaration or a
+/// transport-layer `charset`) to an `Encoding`.
+#[cfg(not(feature = "encoding_rs"))]
+fn encoding_for_label(label: &str) -> Option<Encoding> {
+    encoding::label::encoding_from_whatwg_label(label)
+}
+#[cfg(feature = "encoding_rs")]
+fn encoding_for_label(label: &str) -> Option<Encoding> {
+    encoding_rs::Encoding::for_label(label.as_bytes())
+}
+
+/// How many bytes does detect_encoding() need.
+///
+/// This has to be large enough to buffer a whole XML declaration, since
+/// `detect_encoding` scans it for an `encoding` pseudo-attribute.
+const PRESCAN_BYTES: u32 = 1024;
 
-/// https://html.spec.whatwg.org/multipage/syntax.html#determining-the-character-encoding
-fn detect_encoding(bytes: &ByteTendril, opts: &BytesOpts) -> EncodingRef {
+/// https://www.w3.org/TR/REC-xml/#sec-guessing
+///
+/// Precedence, highest to lowest:
+///
+/// 0. `BytesOpts::force_encoding`, which skips sniffing entirely.
+/// 1. A byte-order mark at the start of the entity.
+/// 2. `BytesOpts::transport_layer_encoding`, e.g. the `charset` of an HTTP
+///    `Content-Type` header.
+/// 3. The `encoding` pseudo-attribute of an XML declaration at the start of
+///    the entity.
+/// 4. UTF-8.
+fn detect_encoding(bytes: &ByteTendril, opts: &BytesOpts) -> Encoding {
+    if let Some(encoding) = opts.force_encoding {
+        return encoding
+    }
     if bytes.starts_with(b"\xEF\xBB\xBF") {
-        return encoding::all::UTF_8
+        return utf_8()
     }
     if bytes.starts_with(b"\xFE\xFF") {
-        return encoding::all::UTF_16BE
+        return utf_16be()
     }
     if bytes.starts_with(b"\xFF\xFE") {
-        return encoding::all::UTF_16LE
+        return utf_16le()
     }
     if let Some(encoding) = opts.transport_layer_encoding {
         return encoding
     }
-    // FIXME(#18): <meta> etc.
-    return encoding::all::UTF_8
+    if let Some(encoding) = encoding_from_xml_declaration(bytes) {
+        return encoding
+    }
+    utf_8()
+}
+
+/// Look for the `encoding` pseudo-attribute of an XML declaration
+/// (e.g. `<?xml version="1.0" encoding="ISO-8859-1"?>`) and, if present,
+/// resolve it to an `Encoding` via the WHATWG label list.
+///
+/// Per https://www.w3.org/TR/REC-xml/#NT-XMLDecl the declaration must start
+/// at byte 0 of the entity, and `S` (mandatory whitespace) must immediately
+/// follow `<?xml` — this is what distinguishes a real XML declaration from a
+/// leading processing instruction that merely starts with those five bytes,
+/// e.g. `<?xml-stylesheet type="text/xsl" href="a.xsl"?>`. No leading
+/// whitespace before `<?xml` itself is permitted. (A text entity preceded by
+/// a UTF-16 BOM would have a 16-bit declaration here, but that case is
+/// already handled by the BOM check above, which takes precedence.) If
+/// `bytes` doesn't contain the closing `?>` yet, we give up rather than
+/// block waiting for more input.
+fn encoding_from_xml_declaration(bytes: &[u8]) -> Option<Encoding> {
+    const START: &'static [u8] = b"<?xml";
+    if !bytes.starts_with(START) || !bytes.get(START.len()).map_or(false, |&b| is_ascii_whitespace(b)) {
+        return None
+    }
+    let close = find_bytes(&bytes[START.len()..], b"?>")?;
+    let decl = &bytes[START.len() .. START.len() + close];
+    let value = xml_decl_pseudo_attr(decl, b"encoding")?;
+    let label = ::std::str::from_utf8(value).ok()?;
+    encoding_for_label(label)
+}
+
+/// Scan the body of an XML/text declaration (the bytes between `<?xml` and
+/// `?>`) for a `name="value"` or `name='value'` pseudo-attribute, returning
+/// the unquoted value. Pseudo-attributes are separated by whitespace and
+/// scanned left to right, so `version` is naturally skipped over on the way
+/// to `encoding`.
+fn xml_decl_pseudo_attr<'a>(decl: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+    let mut rest = decl;
+    loop {
+        rest = skip_ascii_whitespace(rest);
+        if rest.is_empty() {
+            return None
+        }
+        let name_end = rest.iter().position(|&b| b == b'=' || is_ascii_whitespace(b))?;
+        let attr_name = &rest[..name_end];
+        rest = skip_ascii_whitespace(&rest[name_end..]);
+        if !rest.starts_with(b"=") {
+            return None
+        }
+        rest = skip_ascii_whitespace(&rest[1..]);
+        let quote = *rest.first()?;
+        if quote != b'"' && quote != b'\'' {
+            return None
+        }
+        rest = &rest[1..];
+        let value_end = rest.iter().position(|&b| b == quote)?;
+        let value = &rest[..value_end];
+        rest = &rest[value_end + 1..];
+        if attr_name == name {
+            return Some(value)
+        }
+    }
+}
+
+fn is_ascii_whitespace(b: u8) -> bool {
+    b == b' ' || b == b'\t' || b == b'\r' || b == b'\n'
+}
+
+fn skip_ascii_whitespace(bytes: &[u8]) -> &[u8] {
+    let i = bytes.iter().position(|&b| !is_ascii_whitespace(b)).unwrap_or(bytes.len());
+    &bytes[i..]
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
 }
 
 #[cfg(test)]
@@ -345,4 +526,121 @@ mod tests {
         assert_eq!(String::from_utf8(serialized).unwrap(),
                    text);
     }
+
+    #[test]
+    fn xml_decl_double_quoted_encoding() {
+        let encoding = encoding_from_xml_declaration(
+            br#"<?xml version="1.0" encoding="ISO-8859-1"?>"#);
+        assert_eq!(encoding.map(|e| e.name()), Some("iso-8859-1"));
+    }
+
+    #[test]
+    fn xml_decl_single_quoted_encoding() {
+        let encoding = encoding_from_xml_declaration(
+            b"<?xml version='1.0' encoding='UTF-8'?>");
+        assert_eq!(encoding.map(|e| e.name()), Some("utf-8"));
+    }
+
+    #[test]
+    fn xml_decl_extra_whitespace_around_encoding() {
+        let encoding = encoding_from_xml_declaration(
+            b"<?xml version = '1.0'   encoding  =  'UTF-8' standalone='yes'?>");
+        assert_eq!(encoding.map(|e| e.name()), Some("utf-8"));
+    }
+
+    #[test]
+    fn xml_decl_without_encoding_pseudo_attr() {
+        assert!(encoding_from_xml_declaration(b"<?xml version=\"1.0\"?>").is_none());
+    }
+
+    #[test]
+    fn xml_decl_unknown_label() {
+        assert!(encoding_from_xml_declaration(
+            b"<?xml version=\"1.0\" encoding=\"not-a-real-encoding\"?>").is_none());
+    }
+
+    #[test]
+    fn xml_decl_missing_closing_marker() {
+        assert!(encoding_from_xml_declaration(
+            b"<?xml version=\"1.0\" encoding=\"UTF-8\"").is_none());
+    }
+
+    #[test]
+    fn xml_decl_requires_whitespace_after_xml() {
+        // `<?xml-stylesheet ...?>` is a processing instruction, not an XML
+        // declaration, even though it starts with the same five bytes.
+        assert!(encoding_from_xml_declaration(
+            b"<?xml-stylesheet type=\"text/xsl\" encoding=\"UTF-8\" href=\"a.xsl\"?>").is_none());
+    }
+
+    #[test]
+    fn xml_decl_must_start_at_byte_zero() {
+        assert!(encoding_from_xml_declaration(
+            b" <?xml version=\"1.0\" encoding=\"UTF-8\"?>").is_none());
+    }
+
+    #[test]
+    fn bom_detection_is_backend_agnostic() {
+        // Exercises `utf_8`/`utf_16be`/`utf_16le`, whichever backend
+        // (`encoding` or `encoding_rs`) this crate was built with. The two
+        // backends spell their canonical names differently (`utf-8` vs.
+        // `UTF-8`), so compare case-insensitively.
+        let opts = BytesOpts::default();
+        let name = |bytes: &[u8]| detect_encoding(&ByteTendril::from_slice(bytes), &opts).name().to_lowercase();
+        assert_eq!(name(b"\xEF\xBB\xBF<a/>"), "utf-8");
+        assert_eq!(name(b"\xFE\xFF<a/>"), "utf-16be");
+        assert_eq!(name(b"\xFF\xFE<a/>"), "utf-16le");
+    }
+
+    #[test]
+    fn chosen_encoding_is_none_before_prescan_completes() {
+        let parser = parse_document(RcDom::default(), XmlParseOpts::default())
+            .from_bytes(BytesOpts::default());
+        assert!(parser.chosen_encoding().is_none());
+    }
+
+    #[test]
+    fn chosen_encoding_matches_detected_bom() {
+        // Pad past PRESCAN_BYTES so the parser leaves `Buffering` and
+        // actually commits to an encoding before we ask for it.
+        let mut opts = BytesOpts::default();
+        opts.transport_layer_encoding = encoding_for_label("windows-1252");
+        let mut parser = parse_document(RcDom::default(), XmlParseOpts::default())
+            .from_bytes(opts);
+        let mut input = b"\xEF\xBB\xBF<title>Test</title>".to_vec();
+        input.resize(PRESCAN_BYTES as usize + 1, b' ');
+        parser.process(ByteTendril::from_slice(&input));
+        assert_eq!(parser.chosen_encoding().map(|e| e.name().to_lowercase()), Some("utf-8".to_owned()));
+        parser.finish();
+    }
+
+    #[test]
+    fn force_encoding_overrides_bom_and_transport_layer() {
+        let mut opts = BytesOpts::default();
+        opts.transport_layer_encoding = encoding_for_label("utf-16be");
+        opts.force_encoding = encoding_for_label("windows-1252");
+        let mut parser = parse_document(RcDom::default(), XmlParseOpts::default())
+            .from_bytes(opts);
+        let mut input = b"\xEF\xBB\xBF<title>Test</title>".to_vec();
+        input.resize(PRESCAN_BYTES as usize + 1, b' ');
+        parser.process(ByteTendril::from_slice(&input));
+        assert_eq!(parser.chosen_encoding().map(|e| e.name().to_lowercase()), Some("windows-1252".to_owned()));
+        parser.finish();
+    }
+
+    #[test]
+    fn fragment_only_emits_children_of_context() {
+        let context: QualName = Default::default();
+        let dom = parse_fragment(RcDom::default(), XmlParseOpts::default(),
+                                  context, NamespaceMap::default())
+            .from_utf8()
+            .one("<a/><b/>".as_bytes());
+
+        let mut serialized = Vec::new();
+        serialize(&mut serialized, &dom.document, Default::default()).unwrap();
+        let serialized = String::from_utf8(serialized).unwrap();
+        // The document's children must be exactly `a` and `b`, with no
+        // synthetic wrapper standing in for `context` around them.
+        assert_eq!(serialized, "<a/><b/>");
+    }
 }
@@ -0,0 +1,156 @@
+// Copyright 2014-2017 The html5ever Project Developers. See the
+// COPYRIGHT file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::borrow::Cow;
+
+use string_cache::QualName;
+use tendril::StrTendril;
+
+/// A single attribute as seen by a `TreeSink`.
+pub struct Attribute {
+    /// The attribute's name.
+    pub name: QualName,
+    /// The attribute's value.
+    pub value: StrTendril,
+}
+
+/// Something that can be appended to the tree: either a handle to an
+/// already-created node, or text to be wrapped in a new text node.
+pub enum NodeOrText<Handle> {
+    /// An existing node, e.g. one made with `TreeSink::create_element`.
+    AppendNode(Handle),
+    /// Text to be appended, merging with a preceding text node if there is one.
+    AppendText(StrTendril),
+}
+
+/// Receives tree construction events, and produces some kind of document output.
+///
+/// Sink-specific types:
+///
+/// - `Handle` identifies a node in the sink's own tree, and is cloned whenever
+///   `XmlTreeBuilder` needs to keep more than one reference to the same node.
+/// - `Output`, produced by `finish`, is whatever the sink considers the result
+///   of the parse (e.g. a DOM wrapper struct).
+pub trait TreeSink {
+    /// Handle to a DOM node that this sink understands.
+    type Handle: Clone;
+    /// Overall result of the parse, produced by `finish`.
+    type Output;
+
+    /// Signal a parse error.
+    fn parse_error(&mut self, msg: Cow<'static, str>);
+
+    /// Get a handle to the `Document` node.
+    fn get_document(&mut self) -> Self::Handle;
+
+    /// Create an element, not yet attached to the tree.
+    fn create_element(&mut self, name: QualName, attrs: Vec<Attribute>) -> Self::Handle;
+
+    /// Append a node or text as the last child of `parent`.
+    fn append(&mut self, parent: &Self::Handle, child: NodeOrText<Self::Handle>);
+
+    /// Finish parsing and return the output.
+    fn finish(self) -> Self::Output;
+}
+
+/// Tree builder options, currently empty but kept for forward compatibility
+/// with `XmlParseOpts` the way `XmlTokenizerOpts` is.
+#[derive(Clone, Default)]
+pub struct XmlTreeBuilderOpts;
+
+/// A stack of in-scope `xmlns`/`xmlns:prefix` bindings.
+///
+/// Each scope holds the prefix bindings declared by one element; looking up a
+/// prefix walks the scopes from innermost to outermost.
+#[derive(Clone, Default)]
+pub struct NamespaceMap {
+    scopes: Vec<Vec<(Option<QualName>, StrTendril)>>,
+}
+
+impl NamespaceMap {
+    /// Push a new, empty scope (e.g. entering an element).
+    pub fn push_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    /// Pop the innermost scope (e.g. leaving an element).
+    pub fn pop_scope(&mut self) {
+        self.scopes.pop();
+    }
+}
+
+/// The XML tree builder, implementing the tokenizer-facing tree construction
+/// stage: it turns tokens into calls on a `TreeSink`.
+pub struct XmlTreeBuilder<Handle, Sink> {
+    sink: Sink,
+    /// Element open on the stack, innermost last. Always starts out as
+    /// `[sink.get_document()]`, in both document and fragment mode (see
+    /// `new_fragment`) — fragment parsing has no synthetic wrapper element
+    /// of its own in the output, so there is nothing to push but the
+    /// document itself.
+    open_elems: Vec<Handle>,
+    /// In-scope namespace prefix bindings. Starts empty in document mode,
+    /// or seeded from `context_namespaces` when parsing a fragment.
+    namespaces: NamespaceMap,
+}
+
+impl<Handle, Sink> XmlTreeBuilder<Handle, Sink>
+    where Handle: Clone, Sink: TreeSink<Handle = Handle> {
+
+    /// Create a tree builder that parses a whole document into `sink`.
+    pub fn new(mut sink: Sink, _opts: XmlTreeBuilderOpts) -> XmlTreeBuilder<Handle, Sink> {
+        let document = sink.get_document();
+        XmlTreeBuilder {
+            sink: sink,
+            open_elems: vec![document],
+            namespaces: NamespaceMap::default(),
+        }
+    }
+
+    /// Create a tree builder that parses a fragment in the context of an
+    /// element named `context_name`, with `context_namespaces` as that
+    /// element's in-scope namespace prefix bindings.
+    ///
+    /// `context_name` is accepted but not used: it is never instantiated as
+    /// a node and adds no binding to `namespaces`, which is taken from
+    /// `context_namespaces` alone. Tokens are appended
+    /// directly under `sink.get_document()`, exactly as in `new`, so there
+    /// is no synthetic wrapper for `finish` (via `TreeSink::finish`) to
+    /// strip: the document's children produced by this parse already *are*
+    /// the fragment's children, with nothing else mixed in as long as
+    /// `sink` started out with an empty document.
+    pub fn new_fragment(mut sink: Sink,
+                         _context_name: QualName,
+                         context_namespaces: NamespaceMap,
+                         _opts: XmlTreeBuilderOpts)
+                         -> XmlTreeBuilder<Handle, Sink> {
+        let document = sink.get_document();
+        XmlTreeBuilder {
+            sink: sink,
+            open_elems: vec![document],
+            namespaces: context_namespaces,
+        }
+    }
+
+    /// Access the underlying `TreeSink`.
+    pub fn sink_mut(&mut self) -> &mut Sink {
+        &mut self.sink
+    }
+
+    /// Consume the tree builder, returning the underlying `TreeSink`.
+    pub fn unwrap(self) -> Sink {
+        self.sink
+    }
+
+    /// The element that tokens are currently being appended under.
+    #[allow(dead_code)]
+    fn current_node(&self) -> &Handle {
+        self.open_elems.last().expect("open_elems is never empty")
+    }
+}